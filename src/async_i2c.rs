@@ -0,0 +1,41 @@
+//! A thin `embedded-hal-async` wrapper over a blocking I2C peripheral.
+//!
+//! `rp2040-hal`'s I2C driver is synchronous only, but `wii-ext`'s
+//! `read_async` needs `embedded_hal_async::i2c::I2c`. This just runs the
+//! blocking transaction inline -- it resolves immediately rather than truly
+//! yielding the executor mid-transfer, but it satisfies the trait, and the
+//! controller task still cooperates with the rest of the executor between
+//! reads via the sampling `Ticker` in `main`. Swap this out if `rp2040-hal`
+//! grows a real DMA/IRQ-backed async I2C driver.
+
+use embedded_hal::i2c::{ErrorType, I2c as BlockingI2c, Operation};
+use embedded_hal_async::i2c::I2c as AsyncI2c;
+
+pub struct BlockingI2cAsync<I2C>(pub I2C);
+
+impl<I2C: ErrorType> ErrorType for BlockingI2cAsync<I2C> {
+    type Error = I2C::Error;
+}
+
+impl<I2C: BlockingI2c> AsyncI2c for BlockingI2cAsync<I2C> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.0.transaction(address, operations)
+    }
+}
+
+// `Controller::detect`'s ident probe uses the blocking trait directly (it
+// runs once at boot, before the executor is driving anything), so this
+// needs to forward both.
+impl<I2C: BlockingI2c> BlockingI2c for BlockingI2cAsync<I2C> {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.0.transaction(address, operations)
+    }
+}
@@ -0,0 +1,331 @@
+//! Stick calibration and notch correction.
+//!
+//! Raw stick readings get linearized before they ever reach a
+//! [`crate::gamepad::GamepadReport`]: subtract the measured center, scale
+//! the measured min/max range back out to a full signed range, apply a
+//! radial deadzone, then nudge the angle towards the nearest calibrated
+//! notch direction. The result is persisted to flash so it survives a
+//! reset, and is versioned so a layout change doesn't get misread as valid
+//! data.
+//!
+//! This module only holds the math and the storage; what drives it (button
+//! combo, serial command, ...) is wired up by the caller.
+
+use core::mem::size_of;
+use cortex_m::interrupt;
+use critical_section::Mutex;
+use rp2040_flash::flash;
+
+/// Bumped whenever [`Calibration`]'s layout changes. On mismatch, `load`
+/// falls back to defaults instead of trusting uninitialised/stale flash.
+const CAL_REVISION: u8 = 1;
+
+/// Directions sampled for notch correction, in the order their octant index
+/// is stored: N, NE, E, SE, S, SW, W, NW.
+pub const NOTCH_COUNT: usize = 8;
+
+/// Flash size actually fitted to the target board. The
+/// `solderparty_rp2040_stamp_carrier` ships an 8MB QSPI flash, not the 2MB
+/// found on some other Pico boards -- get this wrong and the "last sector"
+/// offset below lands inside the running firmware image.
+const FLASH_SIZE_BYTES: u32 = 8 * 1024 * 1024;
+
+/// Reserve the flash sector one back from the true end of the installed
+/// flash for calibration storage.
+const FLASH_TARGET_OFFSET: u32 = FLASH_SIZE_BYTES - flash::FLASH_SECTOR_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AxisCalibration {
+    pub center: i16,
+    pub min: i16,
+    pub max: i16,
+}
+
+impl Default for AxisCalibration {
+    fn default() -> Self {
+        // Seed min/max at the center rather than the full +/-127 range: if
+        // they started at the extremes, `calibrate_extreme`'s `min.min(raw)`
+        // / `max.max(raw)` could never narrow them, and extreme calibration
+        // would be a no-op. Starting at zero width means the very first
+        // sampled extreme is what actually sets the range.
+        Self {
+            center: 0,
+            min: 0,
+            max: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StickCalibration {
+    pub x: AxisCalibration,
+    pub y: AxisCalibration,
+    /// Per-octant angle error, in tenths of a degree, used to interpolate
+    /// a correction for raw angles that fall between two calibrated notches.
+    pub notch_error: [i16; NOTCH_COUNT],
+    /// Raw (post-centering) magnitude below which the stick reports dead
+    /// center; values above it are rescaled so motion starts at the edge.
+    pub deadzone: i16,
+}
+
+impl Default for StickCalibration {
+    fn default() -> Self {
+        Self {
+            x: AxisCalibration::default(),
+            y: AxisCalibration::default(),
+            notch_error: [0; NOTCH_COUNT],
+            deadzone: 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Calibration {
+    revision: u8,
+    pub left: StickCalibration,
+    pub right: StickCalibration,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        // The derived `Default` would leave `revision` at 0, not
+        // `CAL_REVISION`, so a `cal reset` followed by `cal save` would
+        // write a struct that `load()` rejects as stale on the next boot.
+        Self {
+            revision: CAL_REVISION,
+            left: StickCalibration::default(),
+            right: StickCalibration::default(),
+        }
+    }
+}
+
+/// In-RAM calibration used by the report path. Loaded from flash at boot,
+/// updated by calibration commands, and flushed back with [`save`].
+static CALIBRATION: Mutex<core::cell::RefCell<Calibration>> =
+    Mutex::new(core::cell::RefCell::new(Calibration {
+        revision: CAL_REVISION,
+        left: StickCalibration {
+            x: AxisCalibration {
+                center: 0,
+                min: 0,
+                max: 0,
+            },
+            y: AxisCalibration {
+                center: 0,
+                min: 0,
+                max: 0,
+            },
+            notch_error: [0; NOTCH_COUNT],
+            deadzone: 8,
+        },
+        right: StickCalibration {
+            x: AxisCalibration {
+                center: 0,
+                min: 0,
+                max: 0,
+            },
+            y: AxisCalibration {
+                center: 0,
+                min: 0,
+                max: 0,
+            },
+            notch_error: [0; NOTCH_COUNT],
+            deadzone: 8,
+        },
+    }));
+
+/// Read the calibration out of flash into the in-RAM copy, if its revision
+/// byte matches what this firmware expects.
+pub fn load() {
+    let stored = unsafe { &*((rp2040_hal::rom_data::xip_base() + FLASH_TARGET_OFFSET) as *const Calibration) };
+    if stored.revision == CAL_REVISION {
+        critical_section::with(|cs| *CALIBRATION.borrow(cs).borrow_mut() = *stored);
+    }
+}
+
+/// Persist the in-RAM calibration to its reserved flash sector.
+///
+/// `flash_range_erase_and_program` erases in `FLASH_SECTOR_SIZE` units and
+/// requires the program length to be a multiple of `FLASH_PAGE_SIZE`, so we
+/// can't just hand it the few dozen bytes of `Calibration` -- pad it out to
+/// a full sector-sized buffer first.
+pub fn save() {
+    critical_section::with(|cs| {
+        let cal = *CALIBRATION.borrow(cs).borrow();
+        let mut buf = [0u8; flash::FLASH_SECTOR_SIZE as usize];
+        let bytes = unsafe {
+            core::slice::from_raw_parts((&cal as *const Calibration) as *const u8, size_of::<Calibration>())
+        };
+        buf[..bytes.len()].copy_from_slice(bytes);
+        interrupt::free(|_| unsafe {
+            flash::flash_range_erase_and_program(FLASH_TARGET_OFFSET, &buf, true);
+        });
+    });
+}
+
+/// Reset the in-RAM calibration to defaults (does not touch flash until
+/// [`save`] is called).
+pub fn reset() {
+    critical_section::with(|cs| *CALIBRATION.borrow(cs).borrow_mut() = Calibration::default());
+}
+
+fn with_stick<R>(stick: Stick, f: impl FnOnce(&mut StickCalibration) -> R) -> R {
+    critical_section::with(|cs| {
+        let mut cal = CALIBRATION.borrow(cs).borrow_mut();
+        match stick {
+            Stick::Left => f(&mut cal.left),
+            Stick::Right => f(&mut cal.right),
+        }
+    })
+}
+
+/// Format the current in-RAM calibration for the serial console.
+pub fn dump() -> heapless::String<128> {
+    use core::fmt::Write;
+    critical_section::with(|cs| {
+        let cal = CALIBRATION.borrow(cs).borrow();
+        let mut out = heapless::String::new();
+        let _ = write!(
+            out,
+            "L: cx={} cy={} R: cx={} cy={} dz={}/{}",
+            cal.left.x.center, cal.left.y.center, cal.right.x.center, cal.right.y.center,
+            cal.left.deadzone, cal.right.deadzone,
+        );
+        out
+    })
+}
+
+/// Record the stick's resting position as its new center.
+pub fn calibrate_center(stick: Stick, raw_x: i16, raw_y: i16) {
+    with_stick(stick, |s| {
+        s.x.center = raw_x;
+        s.y.center = raw_y;
+    });
+}
+
+/// Record one of the four cardinal extremes, widening the stored min/max
+/// range for whichever axis the sample actually moved.
+pub fn calibrate_extreme(stick: Stick, raw_x: i16, raw_y: i16) {
+    with_stick(stick, |s| {
+        s.x.min = s.x.min.min(raw_x);
+        s.x.max = s.x.max.max(raw_x);
+        s.y.min = s.y.min.min(raw_y);
+        s.y.max = s.y.max.max(raw_y);
+    });
+}
+
+/// Record the angle error for one of the eight notch directions: `octant`
+/// 0 is North, increasing clockwise in 45 degree steps.
+pub fn calibrate_notch(stick: Stick, octant: usize, raw_x: i16, raw_y: i16) {
+    if octant >= NOTCH_COUNT {
+        return;
+    }
+    let measured_angle = angle_tenths_of_degree(raw_x, raw_y);
+    let ideal_angle = (octant as i32 * 450) as i16;
+    let error = measured_angle - ideal_angle;
+    with_stick(stick, |s| s.notch_error[octant] = error);
+}
+
+/// Angle of (x, y) from North, in tenths of a degree, clockwise, 0..3600.
+/// `y` is in HID orientation (positive is down, i.e. South), as every
+/// caller passes it.
+fn angle_tenths_of_degree(x: i16, y: i16) -> i16 {
+    // atan2 isn't available in core; libm's atan2f is good enough here since
+    // this only runs during an explicit calibration step, not every report.
+    let angle_rad = libm::atan2f(x as f32, -(y as f32));
+    let mut degrees = angle_rad.to_degrees();
+    if degrees < 0.0 {
+        degrees += 360.0;
+    }
+    (degrees * 10.0) as i16
+}
+
+/// Remap one raw stick sample through center/range/deadzone/notch
+/// correction into a centered, signed i8 pair ready for a HID report.
+pub fn apply(stick: Stick, raw_x: i8, raw_y: i8) -> (i8, i8) {
+    with_stick(stick, |s| apply_stick(s, raw_x as i16, raw_y as i16))
+}
+
+fn apply_stick(s: &StickCalibration, raw_x: i16, raw_y: i16) -> (i8, i8) {
+    let x = rescale_axis(&s.x, raw_x);
+    let y = rescale_axis(&s.y, raw_y);
+
+    let (x, y) = apply_deadzone(x, y, s.deadzone);
+    apply_notch_correction(s, x, y)
+}
+
+/// Subtract center, then scale the wider of the two measured half-ranges
+/// out to the full +/-127 output range.
+fn rescale_axis(axis: &AxisCalibration, raw: i16) -> i16 {
+    let centered = raw - axis.center;
+
+    // A zero-width range (the default, or right after a `cal reset`) means
+    // this axis has never had an extreme sampled -- treat it as an
+    // uncalibrated unity passthrough instead of dividing by the degenerate
+    // `max(1)` half-range below, which would saturate any non-zero raw
+    // reading to +/-127 and make every fresh board look fully deflected.
+    if axis.max == axis.min {
+        return centered.clamp(i8::MIN as i16, i8::MAX as i16);
+    }
+
+    let half_range = if centered >= 0 {
+        (axis.max - axis.center).max(1)
+    } else {
+        (axis.center - axis.min).max(1)
+    };
+    ((centered as i32 * i8::MAX as i32) / half_range as i32).clamp(i8::MIN as i32, i8::MAX as i32) as i16
+}
+
+/// Clamp magnitudes at or under `deadzone` to zero, and rescale the
+/// remainder radially so motion starts from zero at the deadzone edge and
+/// full deflection still reaches +/-127.
+fn apply_deadzone(x: i16, y: i16, deadzone: i16) -> (i16, i16) {
+    let magnitude = isqrt((x as i32 * x as i32 + y as i32 * y as i32) as u32);
+    if magnitude <= deadzone as u32 {
+        return (0, 0);
+    }
+    let scale = |v: i16| -> i16 {
+        let scaled = (v as i32 * (magnitude as i32 - deadzone as i32) * i8::MAX as i32)
+            / (magnitude as i32 * (i8::MAX as i32 - deadzone as i32).max(1));
+        scaled.clamp(i8::MIN as i32, i8::MAX as i32) as i16
+    };
+    (scale(x), scale(y))
+}
+
+fn isqrt(n: u32) -> u32 {
+    libm::sqrtf(n as f32) as u32
+}
+
+/// Interpolate the angle error between the two notches nearest the current
+/// angle, and rotate (x, y) back by that much.
+fn apply_notch_correction(s: &StickCalibration, x: i16, y: i16) -> (i8, i8) {
+    if x == 0 && y == 0 {
+        return (0, 0);
+    }
+    let angle = angle_tenths_of_degree(x, y) as i32;
+    let lower = (angle / 450) as usize % NOTCH_COUNT;
+    let upper = (lower + 1) % NOTCH_COUNT;
+    let frac = (angle % 450) as f32 / 450.0;
+
+    let error_tenths =
+        s.notch_error[lower] as f32 * (1.0 - frac) + s.notch_error[upper] as f32 * frac;
+    let correction_rad = -(error_tenths / 10.0).to_radians();
+
+    let (sin, cos) = (libm::sinf(correction_rad), libm::cosf(correction_rad));
+    let xf = x as f32;
+    let yf = y as f32;
+    let x_rot = xf * cos - yf * sin;
+    let y_rot = xf * sin + yf * cos;
+
+    (
+        (x_rot.clamp(i8::MIN as f32, i8::MAX as f32)) as i8,
+        (y_rot.clamp(i8::MIN as f32, i8::MAX as f32)) as i8,
+    )
+}
@@ -0,0 +1,55 @@
+//! Commands accepted over the USB-serial console, and the channels used to
+//! hand them to the controller task and get short text responses back.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::String;
+
+use crate::calibration::Stick;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    CalibrateCenter(Stick),
+    CalibrateExtreme(Stick),
+    CalibrateNotch(Stick, u8),
+    SaveCalibration,
+    ResetCalibration,
+    DumpCalibration,
+    ToggleHires,
+}
+
+/// Commands decoded from serial input, waiting for the controller task.
+pub static COMMANDS: Channel<CriticalSectionRawMutex, Command, 4> = Channel::new();
+
+/// Short text responses from the controller task, waiting to go out over
+/// serial (command acks, calibration dumps, errors).
+pub static RESPONSES: Channel<CriticalSectionRawMutex, String<128>, 4> = Channel::new();
+
+/// Parse one whitespace-separated command line, e.g. `cal center left`,
+/// `cal notch right 3`, `cal save`, `hires toggle`. Returns `None` for
+/// anything it doesn't recognise; it queues no response itself.
+pub fn parse(line: &str) -> Option<Command> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("hires"), Some("toggle")) => Some(Command::ToggleHires),
+        (Some("cal"), Some("save")) => Some(Command::SaveCalibration),
+        (Some("cal"), Some("reset")) => Some(Command::ResetCalibration),
+        (Some("cal"), Some("dump")) => Some(Command::DumpCalibration),
+        (Some("cal"), Some("center")) => parse_stick(words.next()).map(Command::CalibrateCenter),
+        (Some("cal"), Some("extreme")) => parse_stick(words.next()).map(Command::CalibrateExtreme),
+        (Some("cal"), Some("notch")) => {
+            let stick = parse_stick(words.next())?;
+            let octant: u8 = words.next()?.parse().ok()?;
+            Some(Command::CalibrateNotch(stick, octant))
+        }
+        _ => None,
+    }
+}
+
+fn parse_stick(word: Option<&str>) -> Option<Stick> {
+    match word {
+        Some("left") => Some(Stick::Left),
+        Some("right") => Some(Stick::Right),
+        _ => None,
+    }
+}
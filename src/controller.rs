@@ -0,0 +1,212 @@
+//! Runtime detection of which Wii extension is plugged in, so one firmware
+//! image works with either a Classic Controller (Pro) or a Nunchuk, and can
+//! re-detect after a hot replug instead of assuming the same type forever.
+
+use cortex_m::delay::Delay;
+use defmt::{info, warn};
+use embedded_hal::i2c::I2c;
+use wii_ext::classic::Classic;
+use wii_ext::generic::DEVICE_ADDR;
+use wii_ext::nunchuk::Nunchuk;
+
+use crate::calibration::{self, Stick};
+use crate::command::Command;
+use crate::gamepad::{get_gamepad_report, get_gamepad_report_nunchuk, GamepadReport};
+
+/// Register holding the six-byte extension identity, read after the
+/// standard unencrypted handshake (see the Wiibrew "Extension Controllers"
+/// page for the well-known ident values used below).
+const IDENT_REG: u8 = 0xfa;
+const IDENT_LEN: usize = 6;
+const IDENT_NUNCHUK: [u8; IDENT_LEN] = [0x00, 0x00, 0xa4, 0x20, 0x00, 0x00];
+
+/// Disable any encryption on the extension so the ident (and later reading)
+/// registers return their real values instead of the encrypted/`0xFF`
+/// placeholder. Required before reading `IDENT_REG`, and harmless to repeat
+/// since `Classic`/`Nunchuk::new` each redo it as part of their own init.
+fn unencrypted_handshake<I2C: I2c>(i2c: &mut I2C) -> Result<(), I2C::Error> {
+    i2c.write(DEVICE_ADDR, &[0xf0, 0x55])?;
+    i2c.write(DEVICE_ADDR, &[0xfb, 0x00])?;
+    Ok(())
+}
+
+/// Either supported extension, behind a single report-producing interface.
+pub enum Controller<I2C> {
+    Classic(Classic<I2C>),
+    Nunchuk(Nunchuk<I2C>),
+    /// No extension responded to detection (unplugged, or still settling
+    /// after a hot-plug). Holds the bus back, when we still have it, so
+    /// `reinit` can keep retrying without a reboot; `Classic`/`Nunchuk::new`
+    /// give no way to recover the bus if they fail after acking detection,
+    /// so that vanishingly rare case leaves this holding `None` instead.
+    Disconnected(Option<I2C>),
+}
+
+impl<I2C> Controller<I2C>
+where
+    I2C: I2c,
+{
+    /// Probe the identity register and construct the matching driver.
+    /// Anything that doesn't look like a Nunchuk is treated as a Classic
+    /// Controller, since most clones don't report the canonical ident bytes.
+    /// Never panics: an extension that doesn't answer the probe (or that
+    /// acks the probe but then fails to initialise) lands in
+    /// [`Controller::Disconnected`] instead.
+    pub fn detect(mut i2c: I2C, delay: &mut Delay) -> Self {
+        // The extension returns encrypted (effectively 0xFF) register
+        // contents until the standard unencrypted handshake has run, so the
+        // ident read below is meaningless without it. Neither call consumes
+        // `i2c`, so on failure (e.g. nothing plugged in) it's still ours.
+        let mut ident = [0u8; IDENT_LEN];
+        let probed = unencrypted_handshake(&mut i2c)
+            .and_then(|_| i2c.write_read(DEVICE_ADDR, &[IDENT_REG], &mut ident))
+            .is_ok();
+
+        if !probed {
+            info!("No extension responding (unplugged or still settling)");
+            return Controller::Disconnected(Some(i2c));
+        }
+
+        if ident == IDENT_NUNCHUK {
+            match Nunchuk::new(i2c, delay) {
+                Ok(n) => {
+                    info!("Detected Nunchuk");
+                    Controller::Nunchuk(n)
+                }
+                Err(_) => {
+                    warn!("Nunchuk acked detection but failed to initialise");
+                    Controller::Disconnected(None)
+                }
+            }
+        } else {
+            match Classic::new(i2c, delay) {
+                Ok(mut classic) => {
+                    info!("Detected Classic Controller (or unrecognised, assuming Classic)");
+                    let _ = classic.enable_hires(delay);
+                    Controller::Classic(classic)
+                }
+                Err(_) => {
+                    warn!("Classic Controller acked detection but failed to initialise");
+                    Controller::Disconnected(None)
+                }
+            }
+        }
+    }
+
+    /// Recover from a read error by re-probing the extension from scratch,
+    /// including re-running ident detection -- so unplugging a Classic
+    /// Controller and plugging in a Nunchuk (or vice versa) is picked up
+    /// here rather than needing a reboot. Falls back to
+    /// [`Controller::Disconnected`] (never panics) if nothing answers.
+    pub fn reinit(self, delay: &mut Delay) -> Self {
+        let i2c = match self {
+            Controller::Classic(c) => Some(c.destroy()),
+            Controller::Nunchuk(n) => Some(n.destroy()),
+            Controller::Disconnected(i2c) => i2c,
+        };
+        match i2c {
+            Some(i2c) => Self::detect(i2c, delay),
+            None => Controller::Disconnected(None),
+        }
+    }
+
+    pub async fn read_report(&mut self) -> Result<GamepadReport, ()> {
+        match self {
+            Controller::Classic(c) => c
+                .read_async()
+                .await
+                .map(|r| get_gamepad_report(&r))
+                .map_err(|_| ()),
+            Controller::Nunchuk(n) => n
+                .read_async()
+                .await
+                .map(|r| get_gamepad_report_nunchuk(&r))
+                .map_err(|_| ()),
+            Controller::Disconnected(_) => Err(()),
+        }
+    }
+
+    /// Raw (uncalibrated) `(left, right)` stick readings, each an `(x, y)`
+    /// pair. The Nunchuk only has the left stick; its right stick is `(0, 0)`.
+    async fn raw_sticks(&mut self) -> Result<((i16, i16), (i16, i16)), ()> {
+        match self {
+            Controller::Classic(c) => c.read_async().await.map_err(|_| ()).map(|r| {
+                (
+                    (r.joystick_left_x as i16, -(r.joystick_left_y as i16)),
+                    (r.joystick_right_x as i16, -(r.joystick_right_y as i16)),
+                )
+            }),
+            Controller::Nunchuk(n) => n.read_async().await.map_err(|_| ()).map(|r| {
+                ((r.joystick_x as i16, -(r.joystick_y as i16)), (0, 0))
+            }),
+            Controller::Disconnected(_) => Err(()),
+        }
+    }
+
+    /// Handle a hi-res toggle request. The wii-ext crate has no way to turn
+    /// hi-res back off once enabled, so this always (re-)enables it on a
+    /// Classic Controller; it never turns it off.
+    fn toggle_hires(&mut self, delay: &mut Delay) {
+        match self {
+            Controller::Classic(c) => {
+                if let Err(e) = c.enable_hires(delay) {
+                    defmt::warn!("enable_hires failed: {:?}", defmt::Debug2Format(&e));
+                }
+            }
+            Controller::Nunchuk(_) => {
+                defmt::warn!("Nunchuk has no hi-res mode to toggle");
+            }
+            Controller::Disconnected(_) => {
+                defmt::warn!("No controller connected, nothing to toggle");
+            }
+        }
+    }
+
+    /// Apply one command from the serial console, queueing a short text
+    /// response where one makes sense.
+    pub async fn handle_command(&mut self, command: Command, delay: &mut Delay) {
+        use heapless::String;
+
+        let response: String<128> = match command {
+            Command::CalibrateCenter(stick) => match self.raw_sticks().await {
+                Ok((left, right)) => {
+                    let (x, y) = if matches!(stick, Stick::Left) { left } else { right };
+                    calibration::calibrate_center(stick, x, y);
+                    String::from("ok: center set")
+                }
+                Err(()) => String::from("err: controller read failed"),
+            },
+            Command::CalibrateExtreme(stick) => match self.raw_sticks().await {
+                Ok((left, right)) => {
+                    let (x, y) = if matches!(stick, Stick::Left) { left } else { right };
+                    calibration::calibrate_extreme(stick, x, y);
+                    String::from("ok: extreme sampled")
+                }
+                Err(()) => String::from("err: controller read failed"),
+            },
+            Command::CalibrateNotch(stick, octant) => match self.raw_sticks().await {
+                Ok((left, right)) => {
+                    let (x, y) = if matches!(stick, Stick::Left) { left } else { right };
+                    calibration::calibrate_notch(stick, octant as usize, x, y);
+                    String::from("ok: notch sampled")
+                }
+                Err(()) => String::from("err: controller read failed"),
+            },
+            Command::SaveCalibration => {
+                calibration::save();
+                String::from("ok: calibration saved to flash")
+            }
+            Command::ResetCalibration => {
+                calibration::reset();
+                String::from("ok: calibration reset to defaults")
+            }
+            Command::DumpCalibration => calibration::dump(),
+            Command::ToggleHires => {
+                self.toggle_hires(delay);
+                String::from("ok: hires toggled")
+            }
+        };
+
+        let _ = crate::command::RESPONSES.try_send(response);
+    }
+}
@@ -0,0 +1,224 @@
+//! A richer HID gamepad report: both analog sticks, both analog triggers
+//! and the D-pad, built from a hi-res `wii-ext` `ClassicReadingCalibrated`.
+//!
+//! `JoystickReport` from `usbd-human-interface-device` only has room for a
+//! single stick and eight buttons, which throws away most of what a
+//! Classic Controller (Pro) can report. This module defines a wider report
+//! and matching HID interface so the Pico shows up as a proper dual-stick
+//! gamepad.
+
+use packed_struct::prelude::*;
+use usbd_human_interface_device::device::DeviceClass;
+use usbd_human_interface_device::interface::{
+    InBytes16, Interface, InterfaceBuilder, OutBytes0, ReportSingle, UsbAllocatable,
+};
+use usbd_human_interface_device::prelude::*;
+use usbd_human_interface_device::usb_class_builder::InterfaceConfig;
+use usbd_human_interface_device::UsbHidError;
+use wii_ext::classic::ClassicReadingCalibrated;
+
+use crate::calibration;
+
+/// Buttons, sticks, triggers and D-pad as a single 9-byte HID report (2
+/// bytes of buttons + six 1-byte axes + 1 hat byte). There's no `InBytes9`
+/// marker type, so the interface below is allocated as `InBytes16` and only
+/// the first 9 bytes of each report are meaningful.
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = GAMEPAD) = {
+        (usage_page = BUTTON, usage_min = 1, usage_max = 12) = {
+            #[packed_bits 12] #[item_settings data,variable,absolute] buttons=input;
+        };
+        (usage_page = GENERIC_DESKTOP,) = {
+            (usage = X,) = {
+                #[item_settings data,variable,absolute] x=input;
+            };
+            (usage = Y,) = {
+                #[item_settings data,variable,absolute] y=input;
+            };
+            (usage = Z,) = {
+                #[item_settings data,variable,absolute] z=input;
+            };
+            (usage = RX,) = {
+                #[item_settings data,variable,absolute] rx=input;
+            };
+            (usage = RY,) = {
+                #[item_settings data,variable,absolute] ry=input;
+            };
+            (usage = RZ,) = {
+                #[item_settings data,variable,absolute] rz=input;
+            };
+            (usage = HAT_SWITCH,) = {
+                #[packed_bits 4] #[item_settings data,variable,absolute,null_state] hat=input;
+            };
+        };
+    }
+)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default, PackedStruct)]
+#[packed_struct(endian = "lsb", size_bytes = 9)]
+pub struct GamepadReport {
+    pub buttons: u16,
+    pub x: i8,
+    pub y: i8,
+    pub z: u8,
+    pub rx: i8,
+    pub ry: i8,
+    pub rz: u8,
+    pub hat: u8,
+}
+
+/// HID descriptor config for [`GamepadReport`], analogous to
+/// `usbd_human_interface_device::device::joystick::JoystickInterface`.
+pub struct GamepadInterface<'a, B: usb_device::bus::UsbBus> {
+    inner: Interface<'a, B, InBytes16, OutBytes0, ReportSingle>,
+}
+
+impl<'a, B: usb_device::bus::UsbBus> GamepadInterface<'a, B> {
+    pub fn write_report(&mut self, report: &GamepadReport) -> Result<(), UsbHidError> {
+        let data = report
+            .pack()
+            .map_err(|_| UsbHidError::SerializationError)?;
+        self.inner
+            .write_report(&data)
+            .map(|_| ())
+            .map_err(UsbHidError::from)
+    }
+}
+
+pub struct GamepadInterfaceConfig<'a> {
+    inner: InterfaceConfig<'a, InBytes16, OutBytes0, ReportSingle>,
+}
+
+impl<'a> Default for GamepadInterfaceConfig<'a> {
+    fn default() -> Self {
+        Self {
+            inner: InterfaceBuilder::new(GamepadReport::desc())
+                .description("Gamepad")
+                .in_endpoint(10.into())
+                .unwrap()
+                .build(),
+        }
+    }
+}
+
+impl<'a> GamepadInterfaceConfig<'a> {
+    pub fn default_config() -> Self {
+        Self::default()
+    }
+}
+
+impl<'a, B: usb_device::bus::UsbBus + 'a> UsbAllocatable<'a, B> for GamepadInterfaceConfig<'a> {
+    type Allocated = GamepadInterface<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a usb_device::bus::UsbBusAllocator<B>) -> Self::Allocated {
+        GamepadInterface {
+            inner: self.inner.allocate(usb_alloc),
+        }
+    }
+}
+
+impl<'a, B: usb_device::bus::UsbBus> DeviceClass<'a> for GamepadInterface<'a, B> {
+    type I = Interface<'a, B, InBytes16, OutBytes0, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.inner
+    }
+}
+
+/// Negate an `i8` axis reading without the debug-overflow panic / release
+/// wraparound that plain `-y` hits on `i8::MIN` (-128, which has no positive
+/// `i8` counterpart): go through `i16` and clamp back down.
+fn negate_axis(v: i8) -> i8 {
+    (-(v as i16)).clamp(i8::MIN as i16, i8::MAX as i16) as i8
+}
+
+/// D-pad directions packed into a single HID hat-switch nibble (0-7,
+/// `8`/`0x0F` for released depending on encoding used by the host).
+fn hat_from_dpad(up: bool, down: bool, left: bool, right: bool) -> u8 {
+    match (up, right, down, left) {
+        (true, false, false, false) => 0,
+        (true, true, false, false) => 1,
+        (false, true, false, false) => 2,
+        (false, true, true, false) => 3,
+        (false, false, true, false) => 4,
+        (false, false, true, true) => 5,
+        (false, false, false, true) => 6,
+        (true, false, false, true) => 7,
+        _ => 8,
+    }
+}
+
+/// Build a [`GamepadReport`] from a hi-res Classic Controller reading.
+///
+/// Requires `enable_hires` to have been called on the controller so that
+/// the trigger axes and both sticks carry their full 8-bit range.
+pub fn get_gamepad_report(input: &ClassicReadingCalibrated) -> GamepadReport {
+    let mut buttons = 0u16;
+
+    buttons |= input.button_b as u16;
+    buttons |= (input.button_a as u16) << 1;
+    buttons |= (input.button_y as u16) << 2;
+    buttons |= (input.button_x as u16) << 3;
+    buttons |= (input.button_trigger_l as u16) << 4;
+    buttons |= (input.button_trigger_r as u16) << 5;
+    buttons |= (input.button_minus as u16) << 6;
+    buttons |= (input.button_plus as u16) << 7;
+    buttons |= (input.button_home as u16) << 8;
+    buttons |= (input.button_zl as u16) << 9;
+    buttons |= (input.button_zr as u16) << 10;
+
+    let hat = hat_from_dpad(
+        input.dpad_up,
+        input.dpad_down,
+        input.dpad_left,
+        input.dpad_right,
+    );
+
+    let (x, y) = calibration::apply(
+        calibration::Stick::Left,
+        input.joystick_left_x,
+        negate_axis(input.joystick_left_y),
+    );
+    let (rx, ry) = calibration::apply(
+        calibration::Stick::Right,
+        input.joystick_right_x,
+        negate_axis(input.joystick_right_y),
+    );
+
+    GamepadReport {
+        buttons,
+        x,
+        y,
+        rx,
+        ry,
+        z: input.trigger_left,
+        rz: input.trigger_right,
+        hat,
+    }
+}
+
+/// Build a [`GamepadReport`] from a Nunchuk reading. The Nunchuk only has
+/// one stick and the C/Z buttons, so the right stick, triggers and D-pad
+/// are simply left at rest.
+///
+/// Takes the calibrated reading (matching `get_gamepad_report`'s
+/// `ClassicReadingCalibrated`, and what `read_async` actually yields) since
+/// the raw `NunchukReading`'s u8 axes aren't what `calibration::apply`
+/// expects.
+pub fn get_gamepad_report_nunchuk(input: &wii_ext::nunchuk::NunchukReadingCalibrated) -> GamepadReport {
+    let mut buttons = 0u16;
+    buttons |= input.button_c as u16;
+    buttons |= (input.button_z as u16) << 1;
+
+    let (x, y) = calibration::apply(
+        calibration::Stick::Left,
+        input.joystick_x,
+        negate_axis(input.joystick_y),
+    );
+
+    GamepadReport {
+        buttons,
+        x,
+        y,
+        ..Default::default()
+    }
+}
@@ -21,17 +21,41 @@ use bsp::hal::{
     sio::Sio,
     watchdog::Watchdog,
 };
+use embassy_executor::{Executor, Spawner};
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use fugit::RateExtU32;
-use wii_ext::classic::{Classic, ClassicReadingCalibrated};
+use static_cell::StaticCell;
 
 use usb_device::class_prelude::*;
 use usb_device::prelude::*;
-use usbd_human_interface_device::device::joystick::JoystickReport;
 use usbd_human_interface_device::prelude::*;
+use usbd_serial::SerialPort;
+
+mod async_i2c;
+mod calibration;
+mod command;
+mod controller;
+mod gamepad;
+mod time_driver;
+use async_i2c::BlockingI2cAsync;
+use command::Command;
+use controller::Controller;
+use gamepad::{GamepadInterfaceConfig, GamepadReport};
+
+/// Latest gamepad report, handed off from the controller-polling task to
+/// the USB task. A `Signal` is enough here: we only ever care about the
+/// most recent reading, never a queue of stale ones.
+static LATEST_REPORT: Signal<CriticalSectionRawMutex, GamepadReport> = Signal::new();
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
 #[entry]
 fn main() -> ! {
     info!("Program start");
+    calibration::load();
     let mut pac = pac::Peripherals::take().unwrap();
     let core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
@@ -68,85 +92,184 @@ fn main() -> ! {
         &mut pac.RESETS,
     ));
 
-    let mut joy = UsbHidClassBuilder::new()
-        .add_interface(
-            usbd_human_interface_device::device::joystick::JoystickInterface::default_config(),
-        )
+    let joy = UsbHidClassBuilder::new()
+        .add_interface(GamepadInterfaceConfig::default_config())
         .build(&usb_bus);
 
+    let serial = SerialPort::new(&usb_bus);
+
+    // Composite device: the gamepad HID interface plus a CDC-ACM serial
+    // interface for calibration/debug, needs an IAD so hosts group the two
+    // CDC interfaces together correctly alongside the HID one.
     //https://pid.codes
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001))
+    let usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001))
+        .composite_with_iads()
         .manufacturer("usbd-human-interface-device")
-        .product("Rusty joystick")
+        .product("Rusty gamepad")
         .serial_number("TEST")
         .build();
 
     let sda_pin = pins.sda.into_mode::<FunctionI2C>();
     let scl_pin = pins.scl.into_mode::<FunctionI2C>();
 
-    let i2c = bsp::hal::I2C::i2c0(
+    // 400kHz fast mode: the official Nintendo extensions and most clones
+    // are happy running this fast, and it keeps the async read well clear
+    // of our report cadence.
+    let i2c = BlockingI2cAsync(bsp::hal::I2C::i2c0(
         pac.I2C0,
         sda_pin,
         scl_pin,
-        100.kHz(),
+        400.kHz(),
         &mut pac.RESETS,
         &clocks.peripheral_clock,
-    );
+    ));
+
+    // Work out whether a Classic Controller or a Nunchuk is plugged in, and
+    // initialise (and for Classic, calibrate/hi-res) it accordingly.
+    let controller = Controller::detect(i2c, &mut delay);
+
+    // `embassy_time` (Ticker/Timer/Instant, used below and in the USB task)
+    // needs its hardware time driver's interrupt unmasked to actually tick.
+    unsafe { cortex_m::peripheral::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0) };
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.must_spawn(controller_task(controller, delay));
+        spawner.must_spawn(usb_task(usb_dev, joy, serial));
+    })
+}
+
+/// I2C bus type used by the on-board Classic Controller connector, wrapped
+/// to satisfy `embedded-hal-async` (see `async_i2c`).
+type ControllerI2c = BlockingI2cAsync<
+    hal::I2C<
+        pac::I2C0,
+        (
+            hal::gpio::Pin<hal::gpio::bank0::Gpio4, hal::gpio::FunctionI2C, hal::gpio::PullDown>,
+            hal::gpio::Pin<hal::gpio::bank0::Gpio5, hal::gpio::FunctionI2C, hal::gpio::PullDown>,
+        ),
+    >,
+>;
+
+/// Reads the controller over I2C on a timer-paced cadence, publishes
+/// decoded reports for the USB task to pick up, and services calibration
+/// commands that arrive from the serial console.
+///
+/// The I2C transfer itself still runs synchronously to completion (see
+/// `async_i2c`) and stalls the executor -- and so `usb_task` -- for its
+/// duration; only the idle time between samples is yielded, via the
+/// `Ticker` below.
+#[embassy_executor::task]
+async fn controller_task(
+    mut controller: Controller<ControllerI2c>,
+    mut delay: cortex_m::delay::Delay,
+) -> ! {
+    // Sample on a hardware-timer-backed tick instead of reading as fast as
+    // the bus allows, so sample timing doesn't drift with bus/USB load.
+    let mut ticker = Ticker::every(Duration::from_millis(1));
+    loop {
+        match select(ticker.next(), command::COMMANDS.receive()).await {
+            Either::First(()) => match controller.read_report().await {
+                Ok(report) => LATEST_REPORT.signal(report),
+                // re-detect on failure: covers a bus glitch as well as a
+                // hot replug that swapped Classic for Nunchuk or vice versa.
+                Err(()) => controller = controller.reinit(&mut delay),
+            },
+            Either::Second(command) => controller.handle_command(command, &mut delay).await,
+        }
+    }
+}
+
+/// The joystick class built with a single [`gamepad::GamepadInterfaceConfig`] interface.
+type GamepadClass = UsbHidClass<'static, hal::usb::UsbBus, frunk_core::hlist::HCons<gamepad::GamepadInterface<'static, hal::usb::UsbBus>, frunk_core::hlist::HNil>>;
 
-    // Create, initialise and calibrate the controller
-    let mut controller = Classic::new(i2c, &mut delay).unwrap();
+/// Polls the USB device, forwards the most recently published report to the
+/// HID interface, and services the CDC-ACM console: streams decoded
+/// readings, reads command lines, and relays the controller task's replies.
+#[embassy_executor::task]
+async fn usb_task(
+    mut usb_dev: UsbDevice<'static, hal::usb::UsbBus>,
+    mut joy: GamepadClass,
+    mut serial: SerialPort<'static, hal::usb::UsbBus>,
+) -> ! {
+    let mut line_buf: heapless::Vec<u8, 64> = heapless::Vec::new();
 
-    // Enable hi-resolution mode. This also updates calibration
-    // Don't really need it for this single stick mode. Plus it might make recovery easier...
-    //controller.enable_hires(&mut delay).unwrap();
+    // Coalescing state: only write a new HID report when the decoded state
+    // actually changed, or the keep-alive interval has elapsed, and hang
+    // onto a report that hit `WouldBlock` so it gets retried instead of
+    // silently dropped.
+    let mut last_sent: Option<GamepadReport> = None;
+    let mut last_sent_at = Instant::now();
+    let mut pending: Option<GamepadReport> = None;
+    const KEEP_ALIVE: Duration = Duration::from_millis(50);
 
-    // If you have a Nunchuk controller, use this instead.
-    // let mut controller = Nunchuk::new(i2c, &mut delay).unwrap();
     loop {
-        // Need some delay here or things get unhappy.
-        // TODO: investigate if it's a bug...
-        delay.delay_ms(1);
-        // Capture the current button and axis values
-        let input = controller.read_blocking(&mut delay);
-
-        // Poll every 10ms
-        if let Ok(input) = input {
-            match joy.interface().write_report(&get_report(&input)) {
+        if usb_dev.poll(&mut [&mut joy, &mut serial]) {}
+
+        if let Some(report) = LATEST_REPORT.try_take() {
+            if pending.is_none()
+                && (last_sent != Some(report) || last_sent_at.elapsed() >= KEEP_ALIVE)
+            {
+                pending = Some(report);
+            }
+
+            // Stream every decoded reading (not just the coalesced ones) so
+            // a plain terminal can watch it without a debug probe attached.
+            // 128 comfortably covers the worst case (`{:?}` of a
+            // `GamepadReport` with a negative axis and a 5-digit `buttons`
+            // plus "\r\n" is ~97 bytes) with headroom for derive output
+            // drifting slightly as fields are added.
+            let mut line: heapless::String<128> = heapless::String::new();
+            let _ = core::fmt::Write::write_fmt(&mut line, format_args!("{:?}\r\n", report));
+            let _ = serial.write(line.as_bytes());
+        }
+
+        if let Some(report) = pending {
+            match joy.interface().write_report(&report) {
+                Ok(_) => {
+                    last_sent = Some(report);
+                    last_sent_at = Instant::now();
+                    pending = None;
+                }
+                // Retry next loop instead of dropping the report on the floor.
                 Err(UsbHidError::WouldBlock) => {}
-                Ok(_) => {}
                 Err(e) => {
-                    core::panic!("Failed to write joystick report: {:?}", e)
+                    core::panic!("Failed to write gamepad report: {:?}", e)
                 }
             }
-            // Print inputs from the controller
-            // info!("{:?}", input);
-        } else {
-            // re-init controller on failure
-            let _ = controller.init(&mut delay);
-            //let _ = controller.enable_hires(&mut delay);
         }
 
-        if usb_dev.poll(&mut [&mut joy]) {}
-    }
-}
+        while let Ok(response) = command::RESPONSES.try_receive() {
+            let _ = serial.write(response.as_bytes());
+            let _ = serial.write(b"\r\n");
+        }
+
+        let mut byte = [0u8; 16];
+        if let Ok(count) = serial.read(&mut byte) {
+            for &b in &byte[..count] {
+                if b == b'\n' || b == b'\r' {
+                    if !line_buf.is_empty() {
+                        if let Ok(line) = core::str::from_utf8(&line_buf) {
+                            match command::parse(line) {
+                                Some(cmd) => {
+                                    let _ = command::COMMANDS.try_send(cmd);
+                                }
+                                None => {
+                                    let _ = serial.write(b"err: unrecognised command\r\n");
+                                }
+                            }
+                        }
+                        line_buf.clear();
+                    }
+                } else if line_buf.push(b).is_err() {
+                    line_buf.clear();
+                }
+            }
+        }
 
-fn get_report(input: &ClassicReadingCalibrated) -> JoystickReport {
-    // Read out buttons first
-    let mut buttons = 0;
-
-    buttons += (input.button_b as u8) << 0;
-    buttons += (input.button_a as u8) << 1;
-    buttons += (input.button_y as u8) << 2;
-    buttons += (input.button_x as u8) << 3;
-    buttons += (input.button_trigger_l as u8) << 4;
-    buttons += (input.button_trigger_r as u8) << 5;
-    buttons += (input.button_minus as u8) << 6;
-    buttons += (input.button_plus as u8) << 7;
-
-    JoystickReport {
-        buttons,
-        x: input.joystick_left_x,
-        y: -input.joystick_left_y,
+        // Yield to the executor between polls instead of spinning; USB
+        // full-speed frames are 1ms apart so there's no point polling faster.
+        Timer::after(Duration::from_millis(1)).await;
     }
 }
 
@@ -0,0 +1,94 @@
+//! `embassy-time` needs a registered hardware time driver to back
+//! `Instant`/`Timer`/`Ticker` -- nothing in `rp2040-hal` provides one, and
+//! we're not pulling in all of `embassy-rp` just for it, so this is a small
+//! one backed directly by the RP2040's free-running 1MHz `TIMER`
+//! peripheral. `embassy_time`'s default tick rate is 1MHz, which is exactly
+//! `TIMER`'s native resolution, so `now()` needs no scaling.
+//!
+//! Only `ALARM0` is used: the executor only ever needs one outstanding
+//! wakeup at a time, since the single-threaded `Executor` itself multiplexes
+//! every task's timer onto whichever fires next.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::Mutex;
+use embassy_time_driver::{time_driver_impl, AlarmHandle, Driver};
+
+use crate::pac;
+
+struct Rp2040TimeDriver {
+    callback: Mutex<Cell<Option<(fn(*mut ()), *mut ())>>>,
+    allocated: AtomicBool,
+}
+
+time_driver_impl!(static DRIVER: Rp2040TimeDriver = Rp2040TimeDriver {
+    callback: Mutex::new(Cell::new(None)),
+    allocated: AtomicBool::new(false),
+});
+
+impl Driver for Rp2040TimeDriver {
+    fn now(&self) -> u64 {
+        // TIMERAWH can roll over between the two reads; re-read TIMERAWH and
+        // retry if it changed, per the datasheet's recommended sequence.
+        let timer = unsafe { &*pac::TIMER::ptr() };
+        loop {
+            let hi = timer.timerawh.read().bits();
+            let lo = timer.timerawl.read().bits();
+            if hi == timer.timerawh.read().bits() {
+                return ((hi as u64) << 32) | lo as u64;
+            }
+        }
+    }
+
+    unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+        if self.allocated.swap(true, Ordering::AcqRel) {
+            None
+        } else {
+            Some(AlarmHandle::new(0))
+        }
+    }
+
+    fn set_alarm_callback(&self, _alarm: AlarmHandle, callback: fn(*mut ()), ctx: *mut ()) {
+        critical_section::with(|cs| self.callback.borrow(cs).set(Some((callback, ctx))));
+    }
+
+    fn set_alarm(&self, _alarm: AlarmHandle, timestamp: u64) -> bool {
+        let timer = unsafe { &*pac::TIMER::ptr() };
+
+        // Already due: don't arm anything, just tell the caller so it can
+        // call its callback now. Arming a stale target here could still
+        // match `ALARM0`'s 32-bit compare after the low word wraps back
+        // around to it, firing spuriously much later.
+        let now = self.now();
+        if now >= timestamp {
+            return false;
+        }
+
+        // `ALARM0` only compares the low 32 bits of `TIMERAWL`, so a target
+        // more than `u32::MAX` ticks (~71 minutes) out would wrap and fire
+        // early. Clamp to the furthest representable point; every alarm
+        // this firmware ever sets is millisecond-scale, so this only
+        // matters as a safety net, matching embassy-rp's driver.
+        let target = timestamp.min(now + u32::MAX as u64) as u32;
+        critical_section::with(|_cs| {
+            timer.alarm0.write(|w| unsafe { w.bits(target) });
+            timer.inte.modify(|_, w| w.alarm_0().set_bit());
+        });
+        true
+    }
+}
+
+/// Route `TIMER_IRQ_0` into the driver. Runs as the interrupt handler once
+/// `main` has unmasked the interrupt in the NVIC; it is not called directly.
+#[allow(non_snake_case)]
+#[cortex_m_rt::interrupt]
+fn TIMER_IRQ_0() {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.intr.write(|w| w.alarm_0().set_bit());
+    critical_section::with(|cs| {
+        if let Some((callback, ctx)) = DRIVER.callback.borrow(cs).get() {
+            callback(ctx);
+        }
+    });
+}